@@ -1,16 +1,35 @@
 #![warn(missing_docs)]
+#![cfg_attr(not(feature = "std"), no_std)]
 //! crate to generate a boot.dat for sx pro from a payload for the switch
+//!
+//! With the `zeroize` feature enabled, the SHA-256 digest fields and the padding of the
+//! internal header structs are wiped when dropped. The temporary `Vec`s that hold a
+//! digest while it is computed or compared are freed without being wiped.
+
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+
+#[cfg(not(feature = "std"))]
+use alloc::format;
+#[cfg(not(feature = "std"))]
+use alloc::string::{String, ToString};
+#[cfg(not(feature = "std"))]
+use alloc::vec;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
 
-use binwrite::{BinWrite, WriterOption};
 use conv::ValueFrom;
+use core::fmt;
+use core::fmt::Formatter;
 use sha2::{Digest, Sha256};
-use std::fmt;
-use std::fmt::Formatter;
-use std::io::Write;
 use thiserror::Error;
 
-#[derive(BinWrite, Debug, Default)]
-#[binwrite(little)]
+#[cfg(feature = "std")]
+use std::io::Write;
+#[cfg(not(feature = "std"))]
+use embedded_io::Write;
+
+#[derive(Debug, Default)]
 /// boot.dat header
 // typedef struct boot_dat_hdr
 // {
@@ -29,8 +48,7 @@ struct BootDatHeader {
     sha2_hdr: Sha2,
 }
 
-#[derive(BinWrite, Debug, Default)]
-#[binwrite(little)]
+#[derive(Debug, Default)]
 struct BootDatInner {
     ident: [u8; 0xc],
     vers: [u8; 0x4],
@@ -52,25 +70,42 @@ pub enum Error {
     HashError,
     /// Error while truncating lengths
     TruncationError,
+    /// A recomputed SHA-256 digest did not match the one embedded in the boot.dat
+    HashMismatch,
 }
 
+#[cfg(feature = "std")]
 impl std::convert::From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
         Error::IoError(err.to_string())
     }
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, fmt: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+#[cfg(not(feature = "std"))]
+impl<E: embedded_io::Error> From<E> for Error {
+    fn from(err: E) -> Self {
+        Error::IoError(format!("{err:?}"))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Error::IoError(s) => write!(fmt, "IO Error: {}", s),
             Error::HashError => write!(fmt, "Hash Error"),
             Error::TruncationError => write!(fmt, "Number Truncation Error"),
+            Error::HashMismatch => write!(fmt, "Hash Mismatch Error"),
         }
     }
 }
 
-// Workaround because Default and BinWrite don't support arrays of this dimension
+/// Size in bytes of the on-disk `boot_dat_hdr_t`
+const HEADER_SIZE: usize = 0x100;
+/// Size in bytes of the inner header covered by `sha2_hdr`
+const INNER_HEADER_SIZE: usize = 0xe0;
+
+// Workaround because Default doesn't support arrays of this dimension
+#[cfg_attr(feature = "zeroize", derive(zeroize::Zeroize, zeroize::ZeroizeOnDrop))]
 struct Pad2([u8; 0x90]);
 
 impl Default for Pad2 {
@@ -85,20 +120,8 @@ impl fmt::Debug for Pad2 {
     }
 }
 
-impl BinWrite for Pad2 {
-    fn write_options<W: Write>(
-        &self,
-        writer: &mut W,
-        options: &WriterOption,
-    ) -> std::io::Result<()> {
-        for item in &self.0 {
-            BinWrite::write_options(item, writer, options)?;
-        }
-        Ok(())
-    }
-}
-
 #[derive(Default)]
+#[cfg_attr(feature = "zeroize", derive(zeroize::Zeroize, zeroize::ZeroizeOnDrop))]
 struct Sha2([u8; 0x20]);
 
 impl fmt::Debug for Sha2 {
@@ -107,17 +130,25 @@ impl fmt::Debug for Sha2 {
     }
 }
 
-impl BinWrite for Sha2 {
-    fn write_options<W: Write>(
-        &self,
-        writer: &mut W,
-        options: &WriterOption,
-    ) -> std::io::Result<()> {
-        for item in &self.0 {
-            BinWrite::write_options(item, writer, options)?;
-        }
-        Ok(())
-    }
+/// Write a `BootDatInner` to `writer` in the on-disk little-endian layout
+fn write_inner<W: Write>(inner: &BootDatInner, writer: &mut W) -> Result<(), Error> {
+    writer.write_all(&inner.ident)?;
+    writer.write_all(&inner.vers)?;
+    writer.write_all(&inner.sha2_s2.0)?;
+    writer.write_all(&inner.s2_dst.to_le_bytes())?;
+    writer.write_all(&inner.s2_size.to_le_bytes())?;
+    writer.write_all(&inner.s2_enc.to_le_bytes())?;
+    writer.write_all(&inner.pad)?;
+    writer.write_all(&inner.s3_size.to_le_bytes())?;
+    writer.write_all(&inner.pad2.0)?;
+    Ok(())
+}
+
+/// Write a full `BootDatHeader` (inner header plus its digest) to `writer`
+fn write_header<W: Write>(header: &BootDatHeader, writer: &mut W) -> Result<(), Error> {
+    write_inner(&header.inner, writer)?;
+    writer.write_all(&header.sha2_hdr.0)?;
+    Ok(())
 }
 
 /// Get the crate version
@@ -129,37 +160,278 @@ pub fn get_version() -> &'static str {
 /// generate a boot.dat given a payload
 /// from <https://gist.github.com/CTCaer/13c02c05daec9e674ba00ce5ac35f5be>
 /// but revisited to match <https://sx-boot-dat-creator.herokuapp.com/> which works for me
-/// `payload` is a byte array of the payload
+/// `payload` is a byte array of the stage-2 payload
+///
+/// This is a thin wrapper around [`BootDatBuilder`] for the common stage-2-only case; use
+/// the builder directly to add a stage-3 payload, mark stage-2 as pre-encrypted, or
+/// customize `s2_dst`.
 ///
 /// # Errors
 /// Returns an Error if there are problem hashing or serializing
 pub fn generate_boot_dat(payload: &[u8]) -> Result<Vec<u8>, Error> {
-    let mut header = BootDatHeader::default();
-    header.inner.ident = [
-        0x49, 0x6e, 0x73, 0x61, 0x6e, 0x65, 0x20, 0x42, 0x4F, 0x4F, 0x54, 0x00,
-    ];
-    header.inner.vers = [0x56, 0x31, 0x2E, 0x30];
-
-    let stage_2_sha256 = sha256_digest(payload);
-    header.inner.sha2_s2 = Sha2(stage_2_sha256.try_into().map_err(|_| Error::HashError)?);
-    header.inner.s2_dst = 0x4001_0000;
-    header.inner.s2_size = u32::value_from(payload.len()).map_err(|_| Error::TruncationError)?;
-
-    let mut inner_serialized = vec![];
-    header.inner.write(&mut inner_serialized)?;
-
-    let header_inner_sha256 = sha256_digest(inner_serialized.as_slice());
-    header.sha2_hdr = Sha2(
-        header_inner_sha256
+    BootDatBuilder::new(payload).build()
+}
+
+/// Default stage-2 load address used by [`BootDatBuilder::new`]
+const DEFAULT_S2_DST: u32 = 0x4001_0000;
+
+/// Builder for a boot.dat, supporting an optional stage-3 payload, a pre-encrypted
+/// stage-2 flag, and a custom stage-2 load address
+///
+/// Defaults match the historical stage-2-only behaviour: no stage-3 payload, `s2_enc`
+/// cleared, and `s2_dst` set to `0x4001_0000`.
+#[derive(Debug)]
+pub struct BootDatBuilder<'a> {
+    stage2: &'a [u8],
+    stage3: Option<&'a [u8]>,
+    stage2_encrypted: bool,
+    dst: u32,
+}
+
+impl<'a> BootDatBuilder<'a> {
+    /// Start building a boot.dat around a stage-2 payload
+    #[must_use]
+    pub fn new(stage2: &'a [u8]) -> Self {
+        BootDatBuilder {
+            stage2,
+            stage3: None,
+            stage2_encrypted: false,
+            dst: DEFAULT_S2_DST,
+        }
+    }
+
+    /// Replace the stage-2 payload
+    #[must_use]
+    pub fn stage2(mut self, stage2: &'a [u8]) -> Self {
+        self.stage2 = stage2;
+        self
+    }
+
+    /// Append a stage-3 payload after the stage-2 one
+    #[must_use]
+    pub fn stage3(mut self, stage3: &'a [u8]) -> Self {
+        self.stage3 = Some(stage3);
+        self
+    }
+
+    /// Mark the stage-2 payload as already encrypted, setting `s2_enc`
+    #[must_use]
+    pub fn stage2_encrypted(mut self, encrypted: bool) -> Self {
+        self.stage2_encrypted = encrypted;
+        self
+    }
+
+    /// Set the stage-2 load address (`s2_dst`), default `0x4001_0000`
+    #[must_use]
+    pub fn dst(mut self, dst: u32) -> Self {
+        self.dst = dst;
+        self
+    }
+
+    /// Build the boot.dat, serializing the header followed by stage-2 and, if set,
+    /// stage-3, and return it as a `Vec`
+    ///
+    /// # Errors
+    /// Returns an Error if there are problem hashing or serializing
+    pub fn build(self) -> Result<Vec<u8>, Error> {
+        let mut serialized = vec![];
+        self.build_to(&mut serialized)?;
+        Ok(serialized)
+    }
+
+    /// Build the boot.dat and stream it straight into `writer`, without buffering the
+    /// header or payloads into an intermediate `Vec`
+    ///
+    /// # Errors
+    /// Returns an Error if there are problem hashing or writing to `writer`
+    pub fn build_to<W: Write>(self, writer: &mut W) -> Result<(), Error> {
+        let mut header = BootDatHeader::default();
+        header.inner.ident = [
+            0x49, 0x6e, 0x73, 0x61, 0x6e, 0x65, 0x20, 0x42, 0x4F, 0x4F, 0x54, 0x00,
+        ];
+        header.inner.vers = [0x56, 0x31, 0x2E, 0x30];
+
+        let stage_2_sha256 = sha256_digest(self.stage2);
+        header.inner.sha2_s2 = Sha2(stage_2_sha256.try_into().map_err(|_| Error::HashError)?);
+        header.inner.s2_dst = self.dst;
+        header.inner.s2_size =
+            u32::value_from(self.stage2.len()).map_err(|_| Error::TruncationError)?;
+        header.inner.s2_enc = u32::from(self.stage2_encrypted);
+        header.inner.s3_size = match self.stage3 {
+            Some(stage3) => u32::value_from(stage3.len()).map_err(|_| Error::TruncationError)?,
+            None => 0,
+        };
+
+        header.sha2_hdr = Sha2(sha256_digest_of_inner(&header.inner)?);
+
+        write_header(&header, writer)?;
+        writer.write_all(self.stage2)?;
+        if let Some(stage3) = self.stage3 {
+            writer.write_all(stage3)?;
+        }
+        Ok(())
+    }
+}
+
+/// Adapter that feeds every byte written through it straight into a `Sha256` hasher, so a
+/// header's digest can be computed without serializing it into a growable `Vec`
+struct HashWriter<'a>(&'a mut Sha256);
+
+#[cfg(feature = "std")]
+impl<'a> Write for HashWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<'a> embedded_io::ErrorType for HashWriter<'a> {
+    type Error = core::convert::Infallible;
+}
+
+#[cfg(not(feature = "std"))]
+impl<'a> Write for HashWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Calc the sha256 digest of a `BootDatInner`'s serialized form without buffering it
+fn sha256_digest_of_inner(inner: &BootDatInner) -> Result<[u8; 0x20], Error> {
+    let mut hasher = Sha256::new();
+    write_inner(inner, &mut HashWriter(&mut hasher))?;
+    hasher
+        .finalize()
+        .as_slice()
+        .try_into()
+        .map_err(|_| Error::HashError)
+}
+
+/// Generate a boot.dat for `payload` and stream it directly into `writer`, computing the
+/// two SHA-256 digests up front; [`generate_boot_dat`] produces the same bytes but
+/// collects them into a returned `Vec`
+///
+/// # Errors
+/// Returns an Error if there are problem hashing or writing to `writer`
+pub fn generate_boot_dat_to<W: Write>(writer: &mut W, payload: &[u8]) -> Result<(), Error> {
+    BootDatBuilder::new(payload).build_to(writer)
+}
+
+/// Fields and payload recovered from an existing boot.dat by [`parse_boot_dat`]
+#[derive(Debug, Clone)]
+pub struct ParsedBootDat {
+    /// `ident` field, decoded as a UTF-8 string with trailing NUL bytes trimmed
+    pub ident: String,
+    /// `vers` field, decoded as a UTF-8 string
+    pub version: String,
+    /// stage-2 load address (`s2_dst`)
+    pub s2_dst: u32,
+    /// stage-2 payload size (`s2_size`)
+    pub s2_size: u32,
+    /// stage-2 encryption flag (`s2_enc`)
+    pub s2_enc: u32,
+    /// stage-3 payload size (`s3_size`)
+    pub s3_size: u32,
+    /// the stage-2 payload extracted from right after the header
+    pub payload: Vec<u8>,
+    /// the stage-3 payload appended after stage-2, if `s3_size` is non-zero
+    pub stage3: Option<Vec<u8>>,
+}
+
+/// Parse and validate an existing boot.dat, recovering its header fields and payload
+///
+/// This is the inverse of [`generate_boot_dat`]: both embedded SHA-256 digests, the
+/// payload digest (`sha2_s2`) and the inner-header digest (`sha2_hdr`), are recomputed
+/// and checked before any data is returned.
+///
+/// # Errors
+/// Returns an Error if `data` is too short to hold a header and its payload, a length
+/// doesn't fit in the expected integer type, or either embedded hash does not match the
+/// recomputed one
+pub fn parse_boot_dat(data: &[u8]) -> Result<ParsedBootDat, Error> {
+    if data.len() < HEADER_SIZE {
+        return Err(Error::TruncationError);
+    }
+
+    let inner_header = &data[..INNER_HEADER_SIZE];
+    let sha2_hdr = &data[INNER_HEADER_SIZE..HEADER_SIZE];
+    if sha256_digest(inner_header) != sha2_hdr {
+        return Err(Error::HashMismatch);
+    }
+
+    let ident = String::from_utf8_lossy(&data[0x00..0x0c])
+        .trim_end_matches('\0')
+        .to_string();
+    let version = String::from_utf8_lossy(&data[0x0c..0x10]).to_string();
+    let sha2_s2 = &data[0x10..0x30];
+    let s2_dst = u32::from_le_bytes(
+        data[0x30..0x34]
+            .try_into()
+            .map_err(|_| Error::TruncationError)?,
+    );
+    let s2_size = u32::from_le_bytes(
+        data[0x34..0x38]
             .try_into()
-            .map_err(|_| Error::HashError)?,
+            .map_err(|_| Error::TruncationError)?,
     );
+    let s2_enc = u32::from_le_bytes(
+        data[0x38..0x3c]
+            .try_into()
+            .map_err(|_| Error::TruncationError)?,
+    );
+    let s3_size = u32::from_le_bytes(
+        data[0x4c..0x50]
+            .try_into()
+            .map_err(|_| Error::TruncationError)?,
+    );
+
+    let payload_len = usize::value_from(s2_size).map_err(|_| Error::TruncationError)?;
+    let payload_end = HEADER_SIZE
+        .checked_add(payload_len)
+        .ok_or(Error::TruncationError)?;
+    let payload = data
+        .get(HEADER_SIZE..payload_end)
+        .ok_or(Error::TruncationError)?
+        .to_vec();
+
+    if sha256_digest(payload.as_slice()) != sha2_s2 {
+        return Err(Error::HashMismatch);
+    }
 
-    let mut serialized = vec![];
-    header.write(&mut serialized)?;
+    let stage3 = if s3_size == 0 {
+        None
+    } else {
+        let stage3_len = usize::value_from(s3_size).map_err(|_| Error::TruncationError)?;
+        let stage3_end = payload_end
+            .checked_add(stage3_len)
+            .ok_or(Error::TruncationError)?;
+        Some(
+            data.get(payload_end..stage3_end)
+                .ok_or(Error::TruncationError)?
+                .to_vec(),
+        )
+    };
 
-    serialized.extend_from_slice(payload);
-    Ok(serialized)
+    Ok(ParsedBootDat {
+        ident,
+        version,
+        s2_dst,
+        s2_size,
+        s2_enc,
+        s3_size,
+        payload,
+        stage3,
+    })
 }
 
 /// Calc sha256 for a byte array
@@ -169,7 +441,7 @@ fn sha256_digest(to_hash: &[u8]) -> Vec<u8> {
     hasher.finalize().to_vec()
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use hex_literal::hex;
     #[test]
@@ -181,4 +453,55 @@ mod tests {
             hex!("6ce4c88e604d351b0e14bca7dbf135b3c8c44428718b704883599f285eed984e")
         );
     }
+
+    #[test]
+    fn round_trip_test() {
+        let payload = [0xa, 0xb, 0xc];
+        let generated = super::generate_boot_dat(&payload).unwrap();
+        let parsed = super::parse_boot_dat(&generated).unwrap();
+        assert_eq!(parsed.payload, payload);
+        assert_eq!(parsed.s2_dst, 0x4001_0000);
+        assert_eq!(parsed.s2_size, payload.len() as u32);
+        assert_eq!(parsed.stage3, None);
+    }
+
+    #[test]
+    fn generate_boot_dat_to_matches_generate_boot_dat() {
+        let payload = [0xa, 0xb, 0xc];
+        let buffered = super::generate_boot_dat(&payload).unwrap();
+
+        let mut streamed = vec![];
+        super::generate_boot_dat_to(&mut streamed, &payload).unwrap();
+
+        assert_eq!(streamed, buffered);
+    }
+
+    #[test]
+    fn builder_appends_stage3_and_sets_flags() {
+        let stage2 = [0xa, 0xb, 0xc];
+        let stage3 = [0xd, 0xe];
+        let generated = super::BootDatBuilder::new(&stage2)
+            .stage3(&stage3)
+            .stage2_encrypted(true)
+            .dst(0x4001_8000)
+            .build()
+            .unwrap();
+        let parsed = super::parse_boot_dat(&generated).unwrap();
+        assert_eq!(parsed.payload, stage2);
+        assert_eq!(parsed.s2_dst, 0x4001_8000);
+        assert_eq!(parsed.s2_enc, 1);
+        assert_eq!(parsed.s3_size, stage3.len() as u32);
+        assert_eq!(parsed.stage3, Some(stage3.to_vec()));
+    }
+
+    #[test]
+    fn corrupted_payload_is_rejected() {
+        let mut generated = super::generate_boot_dat(&[0xa, 0xb, 0xc]).unwrap();
+        let last = generated.len() - 1;
+        generated[last] ^= 0xff;
+        assert!(matches!(
+            super::parse_boot_dat(&generated),
+            Err(super::Error::HashMismatch)
+        ));
+    }
 }